@@ -11,6 +11,7 @@ use futures::{Future, stream, Stream};
 use futures_cpupool::CpuPool;
 use hyper::{Body, Client, Uri};
 use hyper::client::HttpConnector;
+use hyper::header::ContentType;
 use hyper_tls::HttpsConnector;
 use kuchiki;
 use kuchiki::iter::{Select, Elements, Descendants};
@@ -23,9 +24,63 @@ use rusoto_credential::DefaultCredentialsProvider;
 use rusoto_s3::{PutObjectRequest, S3Client, S3};
 use schema::ads;
 use std::collections::HashMap;
+use std::env;
 use server::AdPost;
 
-const ENDPOINT: &'static str = "https://pp-facebook-ads.s3.amazonaws.com/";
+/// Where we archive ad images. Defaults to the `pp-facebook-ads` S3 bucket,
+/// but every field can be overridden so operators can point this at any
+/// S3-compatible service (MinIO, Garage, DigitalOcean Spaces, ...).
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    pub bucket: String,
+    pub region: Region,
+    pub endpoint: Option<String>,
+    pub public_base: String,
+}
+
+impl StorageConfig {
+    pub fn from_env() -> StorageConfig {
+        let bucket = env::var("STORAGE_BUCKET").unwrap_or_else(
+            |_| "pp-facebook-ads".to_string(),
+        );
+        let endpoint = env::var("STORAGE_ENDPOINT").ok();
+        let region = match endpoint {
+            Some(ref endpoint) => {
+                Region::Custom {
+                    name: env::var("STORAGE_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                    endpoint: endpoint.clone(),
+                }
+            }
+            None => Region::UsEast1,
+        };
+        // Path-style by default when a custom endpoint is set (MinIO/Garage
+        // don't give every bucket its own subdomain the way S3 does); only
+        // fall back to the AWS virtual-hosted form when we're really
+        // talking to S3.
+        let public_base = env::var("STORAGE_PUBLIC_BASE").unwrap_or_else(|_| match endpoint {
+            Some(ref endpoint) => format!("{}/{}/", endpoint.trim_right_matches('/'), bucket),
+            None => format!("https://{}.s3.amazonaws.com/", bucket),
+        });
+
+        StorageConfig {
+            bucket: bucket,
+            region: region,
+            endpoint: endpoint,
+            public_base: public_base,
+        }
+    }
+
+    /// The host we expect uploaded images to be served from, used to tell
+    /// images we already archived apart from ones we still need to fetch
+    /// from Facebook's CDN. Derived from `public_base` (not `endpoint`)
+    /// so it always agrees with the URLs `Images::from_ad` actually builds.
+    fn bucket_host(&self) -> String {
+        Url::parse(&self.public_base)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_string()))
+            .unwrap_or_else(|| format!("{}.s3.amazonaws.com", self.bucket))
+    }
+}
 
 fn document_select(
     document: &kuchiki::NodeRef,
@@ -97,6 +152,24 @@ fn get_real_image_uri(uri: Uri) -> Uri {
         .unwrap_or(uri) // Uri
 }
 
+/// Sniff the magic bytes of a downloaded image when the server didn't send a
+/// Content-Type header, so archived images still render inline instead of
+/// downloading as `binary/octet-stream`. A header that is present is used as
+/// is, whatever its value.
+fn sniff_image_mime(bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "image/jpeg"
+    } else if bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        "image/png"
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        "image/gif"
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        "image/webp"
+    } else {
+        "application/octet-stream"
+    }
+}
+
 #[derive(AsChangeset, Debug)]
 #[table_name = "ads"]
 pub struct Images {
@@ -105,24 +178,36 @@ pub struct Images {
     title: String,
     message: String,
     html: String,
+    thumbnail_type: Option<String>,
+    image_types: Vec<String>,
 }
 
 impl Images {
-    fn from_ad(ad: &Ad, images: Vec<Uri>) -> Result<Images> {
+    fn from_ad(ad: &Ad, images: Vec<(Uri, String)>, public_base: &str) -> Result<Images> {
         let thumb = images
             .iter()
-            .filter(|i| ad.thumbnail.contains(i.path()))
-            .map(|i| ENDPOINT.to_string() + i.path().trim_left_matches('/'))
+            .filter(|i| ad.thumbnail.contains(i.0.path()))
+            .map(|i| public_base.to_string() + i.0.path().trim_left_matches('/'))
             .nth(0);
 
+        let thumb_type = images
+            .iter()
+            .find(|i| ad.thumbnail.contains(i.0.path()))
+            .map(|i| i.1.clone());
+
         let mut rest = images.clone();
         if let Some(thumb) = thumb.clone() {
-            rest.retain(|x| !thumb.contains(x.path()))
+            rest.retain(|x| !thumb.contains(x.0.path()))
         };
 
         let collection = rest.iter()
-            .filter(|i| ad.images.iter().any(|a| a.contains(i.path())))
-            .map(|i| ENDPOINT.to_string() + i.path().trim_left_matches('/'))
+            .filter(|i| ad.images.iter().any(|a| a.contains(i.0.path())))
+            .map(|i| public_base.to_string() + i.0.path().trim_left_matches('/'))
+            .collect::<Vec<String>>();
+
+        let collection_types = rest.iter()
+            .filter(|i| ad.images.iter().any(|a| a.contains(i.0.path())))
+            .map(|i| i.1.clone())
             .collect::<Vec<String>>();
 
         let document = kuchiki::parse_html().one(ad.html.clone());
@@ -130,10 +215,10 @@ impl Images {
             if let Some(x) = a.attributes.borrow_mut().get_mut("src") {
                 if let Ok(u) = x.parse::<Uri>() {
                     if let Some(i) = images.iter().find(|i| {
-                        i.path() == get_real_image_uri(u.clone()).path()
+                        i.0.path() == get_real_image_uri(u.clone()).path()
                     })
                     {
-                        *x = ENDPOINT.to_string() + i.path().trim_left_matches('/');
+                        *x = public_base.to_string() + i.0.path().trim_left_matches('/');
                     } else {
                         *x = "".to_string();
                     }
@@ -145,7 +230,9 @@ impl Images {
         let message = get_message(&document)?;
         Ok(Images {
             thumbnail: thumb,
+            thumbnail_type: thumb_type,
             images: collection,
+            image_types: collection_types,
             title: title,
             html: document_select(&document, "div")?
                 .nth(0)
@@ -175,14 +262,34 @@ pub struct Ad {
     pub targeting: Option<String>,
     #[serde(skip_serializing)]
     pub suppressed: bool,
+    pub thumbnail_type: Option<String>,
+    pub image_types: Vec<String>,
 }
 
-// We do this because I can't see how to make sql_function! take a string
-// argument.
-sql_function!(to_englishtsvector, to_englishtsvector_t, (x: Text) -> TsVector);
-sql_function!(to_germantsvector, to_germantsvector_t, (x: Text) -> TsVector);
-sql_function!(to_englishtsquery, to_englishtsquery_t, (x: Text) -> TsQuery);
-sql_function!(to_germantsquery, to_germantsquery_t, (x: Text) -> TsQuery);
+// Diesel's sql_function! needs the target SQL function's name fixed at
+// compile time, so one pair per language would mean a new Rust symbol --
+// and a new match arm everywhere it's used -- for every locale we add.
+// Instead we route every language through this single dynamic pair, backed
+// by `to_dynamic_tsvector`/`to_dynamic_tsquery` SQL functions (see
+// migrations) that take the config name as plain text and cast it to
+// `regconfig` themselves. That leaves `text_search_config` below as the
+// only thing a new language touches.
+sql_function!(to_dynamic_tsvector, to_dynamic_tsvector_t, (config: Text, x: Text) -> TsVector);
+sql_function!(to_dynamic_tsquery, to_dynamic_tsquery_t, (config: Text, x: Text) -> TsQuery);
+
+/// Maps an ad's language code to the Postgres text-search configuration we
+/// stem and rank with -- anything not listed falls back to `english` rather
+/// than silently mis-stemming. Adding a locale is exactly this: one new
+/// match arm here, and nowhere else.
+fn text_search_config(language: &str) -> &'static str {
+    let prefix = language.split('-').next().unwrap_or(language).to_lowercase();
+    match prefix.as_str() {
+        "de" => "german",
+        "fr" => "french",
+        "es" => "spanish",
+        _ => "english",
+    }
+}
 
 impl Ad {
     // This will asynchronously save the images to s3 we may very well end up
@@ -196,19 +303,25 @@ impl Ad {
         client: Client<HttpsConnector<HttpConnector>, Body>,
         db: &Pool<ConnectionManager<PgConnection>>,
         pool: CpuPool,
+        storage: &StorageConfig,
     ) -> Box<Future<Item = (), Error = ()>> {
         let ad = self.clone();
         let pool_s3 = pool.clone();
         let pool_db = pool.clone();
         let db = db.clone();
+        let bucket_host = storage.bucket_host();
+        let filter_bucket_host = bucket_host.clone();
+        let upload_bucket_host = bucket_host.clone();
+        let storage_upload = storage.clone();
+        let storage_save = storage.clone();
         let future = stream::iter_ok(self.image_urls())
             // filter ones we already have in the db and ones we can verify as
             // coming from fb, we don't want to become a malware vector :)
             // currently we redownload images we already have, but ok.
-            .filter(|u| {
+            .filter(move |u| {
                 info!("testing {:?}", u.host());
                 match u.host() {
-                    Some(h) => (h == "pp-facebook-ads.s3.amazonaws.com" || h.ends_with("fbcdn.net")),
+                    Some(h) => (h == filter_bucket_host || h.ends_with("fbcdn.net")),
                     None => false
                 }
             })
@@ -218,31 +331,41 @@ impl Ad {
                 info!("getting {:?}", real_url.path());
                 client
                     .get(real_url.clone())
-                    .and_then(|res| {
-                        res.body().concat2().and_then(|chunk| Ok((chunk, real_url)))
+                    .and_then(move |res| {
+                        let content_type = res.headers().get::<ContentType>().map(
+                            |ct| ct.to_string(),
+                        );
+                        res.body().concat2().and_then(move |chunk| {
+                            let mime = content_type.unwrap_or_else(
+                                || sniff_image_mime(&chunk).to_string(),
+                            );
+                            Ok((chunk, real_url, mime))
+                        })
                     })
                     .map_err(|e| Error::with_chain(e, "Could not get image"))
             })
             // upload them to s3
             .and_then(move |tuple| {
                 let pool = pool_s3.clone();
+                let storage = storage_upload.clone();
                 // we do this in a worker thread because rusoto isn't on
                 // Hyper async yet.
                 pool.spawn_fn(move || {
-                    if tuple.1.host().unwrap() != "pp-facebook-ads.s3.amazonaws.com" {
+                    if tuple.1.host().unwrap() != upload_bucket_host {
                         let credentials = DefaultCredentialsProvider::new()?;
                         let tls = default_tls_client()?;
-                        let client = S3Client::new(tls, credentials, Region::UsEast1);
+                        let client = S3Client::new(tls, credentials, storage.region.clone());
                         let req = PutObjectRequest {
-                            bucket: "pp-facebook-ads".to_string(),
+                            bucket: storage.bucket.clone(),
                             key: tuple.1.path().trim_left_matches('/').to_string(),
                             acl: Some("public-read".to_string()),
+                            content_type: Some(tuple.2.clone()),
                             body: Some(tuple.0.to_vec()),
                             ..PutObjectRequest::default()
                         };
                         client.put_object(&req)?;
                     }
-                    Ok(tuple.1)
+                    Ok((tuple.1, tuple.2))
                 })
             })
             .collect()
@@ -253,7 +376,7 @@ impl Ad {
                 let imgs = images.clone();
                 pool_db.spawn_fn(move || {
                     use schema::ads::dsl::*;
-                    let update = Images::from_ad(&ad, imgs)?;
+                    let update = Images::from_ad(&ad, imgs, &storage_save.public_base)?;
                     let connection = db.get()?;
                     diesel::update(ads.find(&ad.id))
                         .set(&update)
@@ -290,23 +413,34 @@ impl Ad {
             .filter(suppressed.eq(false))
             .into_boxed();
 
-        if let Some(search) = options.get("search") {
-            query = match language {
-                "de-DE" => {
-                    query
-                        .filter(to_germantsvector(html).matches(
-                            to_germantsquery(search.clone()),
-                        ))
-                        .order(ts_rank(to_germantsvector(html), to_germantsquery(search)))
-                }
-                _ => {
-                    query
-                        .filter(to_englishtsvector(html).matches(
-                            to_englishtsquery(search.clone()),
-                        ))
-                        .order(ts_rank(to_englishtsvector(html), to_englishtsquery(search)))
+        // hide anything a standing suppression rule would match, so newly
+        // ingested ads are filtered out before a moderator ever sees them.
+        for rule in SuppressionRule::list(conn)? {
+            let like_pattern = format!("%{}%", escape_like_pattern(&rule.pattern));
+            query = match rule.kind.as_str() {
+                "domain" => query.filter(html.not_like(like_pattern)),
+                "substring" => {
+                    query.filter(
+                        title.not_like(like_pattern.clone()).and(
+                            message.not_like(like_pattern),
+                        ),
+                    )
                 }
-            }
+                "advertiser" => query.filter(title.ne(rule.pattern.clone())),
+                _ => query,
+            };
+        }
+
+        if let Some(search) = options.get("search") {
+            let config = text_search_config(language);
+            query = query
+                .filter(to_dynamic_tsvector(config, html).matches(
+                    to_dynamic_tsquery(config, search.clone()),
+                ))
+                .order(ts_rank(
+                    to_dynamic_tsvector(config, html),
+                    to_dynamic_tsquery(config, search),
+                ));
         }
 
         if let Some(page) = options.get("page") {
@@ -320,6 +454,20 @@ impl Ad {
         )?)
     }
 
+    // Offloads get_ads_by_lang onto a worker thread, same as grab_and_store
+    // does for its Diesel/rusoto work, so a dashboard request doesn't block
+    // the Hyper event loop while Postgres runs the full-text search.
+    pub fn get_ads_by_lang_async(
+        language: String,
+        conn: Pool<ConnectionManager<PgConnection>>,
+        options: HashMap<String, String>,
+        pool: CpuPool,
+    ) -> Box<Future<Item = Vec<Ad>, Error = Error>> {
+        Box::new(pool.spawn_fn(
+            move || Ad::get_ads_by_lang(&language, &conn, &options),
+        ))
+    }
+
     pub fn suppress(adid: String, conn: &Pool<ConnectionManager<PgConnection>>) -> Result<()> {
         use schema::ads::dsl::*;
         let connection = conn.get()?;
@@ -331,6 +479,14 @@ impl Ad {
             .execute(&*connection)?;
         Ok(())
     }
+
+    pub fn suppress_async(
+        adid: String,
+        conn: Pool<ConnectionManager<PgConnection>>,
+        pool: CpuPool,
+    ) -> Box<Future<Item = (), Error = Error>> {
+        Box::new(pool.spawn_fn(move || Ad::suppress(adid, &conn)))
+    }
 }
 
 #[derive(Insertable)]
@@ -413,6 +569,104 @@ impl<'a> NewAd<'a> {
     }
 }
 
+/// Escapes `%`, `_`, and the escape character itself so a rule's pattern is
+/// matched as a literal substring instead of a `LIKE` wildcard expression.
+fn escape_like_pattern(pattern: &str) -> String {
+    pattern.replace('\\', "\\\\").replace('%', "\\%").replace(
+        '_',
+        "\\_",
+    )
+}
+
+/// A standing moderation rule: anything matching it is hidden from
+/// `get_ads_by_lang` as it's ingested, and can be re-applied retroactively
+/// with `apply_all` to clean up ads that slipped through before the rule
+/// existed. `kind` is one of `"domain"` (a link domain found in the ad's
+/// HTML), `"substring"` (a recurring title/message fragment), or
+/// `"advertiser"` (an exact match on the sponsor name we store in `title`).
+#[derive(Serialize, Queryable, Debug, Clone)]
+pub struct SuppressionRule {
+    pub id: i32,
+    pub kind: String,
+    pub pattern: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "suppression_rules"]
+pub struct NewSuppressionRule<'a> {
+    kind: &'a str,
+    pattern: &'a str,
+}
+
+impl SuppressionRule {
+    pub fn add(
+        kind: &str,
+        pattern: &str,
+        conn: &Pool<ConnectionManager<PgConnection>>,
+    ) -> Result<SuppressionRule> {
+        use schema::suppression_rules;
+        let connection = conn.get()?;
+        let new_rule = NewSuppressionRule {
+            kind: kind,
+            pattern: pattern,
+        };
+        Ok(
+            diesel::insert(&new_rule)
+                .into(suppression_rules::table)
+                .get_result(&*connection)?,
+        )
+    }
+
+    pub fn remove(rule_id: i32, conn: &Pool<ConnectionManager<PgConnection>>) -> Result<()> {
+        use schema::suppression_rules::dsl::*;
+        let connection = conn.get()?;
+        diesel::delete(suppression_rules.filter(id.eq(rule_id))).execute(&*connection)?;
+        Ok(())
+    }
+
+    pub fn list(conn: &Pool<ConnectionManager<PgConnection>>) -> Result<Vec<SuppressionRule>> {
+        use schema::suppression_rules::dsl::*;
+        let connection = conn.get()?;
+        Ok(suppression_rules.order(created_at.desc()).load::<SuppressionRule>(
+            &*connection,
+        )?)
+    }
+
+    // Re-applies every active rule retroactively, in case a rule was added
+    // after a matching ad was already ingested.
+    pub fn apply_all(conn: &Pool<ConnectionManager<PgConnection>>) -> Result<usize> {
+        use schema::ads::dsl::*;
+        let connection = conn.get()?;
+        let mut affected = 0;
+        for rule in SuppressionRule::list(conn)? {
+            let like_pattern = format!("%{}%", escape_like_pattern(&rule.pattern));
+            affected += match rule.kind.as_str() {
+                "domain" => {
+                    diesel::update(ads.filter(html.like(like_pattern)))
+                        .set(suppressed.eq(true))
+                        .execute(&*connection)?
+                }
+                "substring" => {
+                    diesel::update(
+                        ads.filter(title.like(like_pattern.clone()).or(
+                            message.like(like_pattern),
+                        )),
+                    ).set(suppressed.eq(true))
+                        .execute(&*connection)?
+                }
+                "advertiser" => {
+                    diesel::update(ads.filter(title.eq(rule.pattern.clone())))
+                        .set(suppressed.eq(true))
+                        .execute(&*connection)?
+                }
+                _ => 0,
+            };
+        }
+        Ok(affected)
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -460,17 +714,49 @@ mod tests {
             targeting: None,
             political_probability: 0.0,
             suppressed: false,
+            thumbnail_type: None,
+            image_types: vec![],
         };
         let urls = saved_ad
             .image_urls()
             .into_iter()
-            .map(|x| x.unwrap())
+            .map(|x| (x.unwrap(), "image/jpeg".to_string()))
             .collect();
-        let images = Images::from_ad(&saved_ad, urls).unwrap();
+        let images = Images::from_ad(&saved_ad, urls, "https://pp-facebook-ads.s3.amazonaws.com/")
+            .unwrap();
         assert!(images.html != saved_ad.html);
         assert!(!images.html.contains("fbcdn"));
         assert!(!images.html.contains("html"));
         assert!(images.images.len() == saved_ad.images.len());
         assert!(images.thumbnail.unwrap() != saved_ad.thumbnail);
+        assert_eq!(images.thumbnail_type.unwrap(), "image/jpeg");
+        assert!(images.image_types.iter().all(|t| t == "image/jpeg"));
+    }
+
+    #[test]
+    fn sniff_image_mime_recognizes_magic_bytes() {
+        assert_eq!(sniff_image_mime(&[0xFF, 0xD8, 0xFF, 0xE0]), "image/jpeg");
+        assert_eq!(
+            sniff_image_mime(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]),
+            "image/png"
+        );
+        assert_eq!(sniff_image_mime(b"GIF89a..."), "image/gif");
+
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&[0, 0, 0, 0]);
+        webp.extend_from_slice(b"WEBP");
+        assert_eq!(sniff_image_mime(&webp), "image/webp");
+
+        assert_eq!(
+            sniff_image_mime(b"not an image"),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn escape_like_pattern_escapes_wildcards_and_backslash() {
+        assert_eq!(escape_like_pattern("100% off_sale"), "100\\% off\\_sale");
+        assert_eq!(escape_like_pattern("a\\b"), "a\\\\b");
+        assert_eq!(escape_like_pattern("plain"), "plain");
     }
 }
@@ -0,0 +1,207 @@
+use diesel::pg::PgConnection;
+use errors::*;
+use futures::{Future, IntoFuture, Stream};
+use futures_cpupool::CpuPool;
+use hyper::{Body, Client, Method, StatusCode};
+use hyper::client::HttpConnector;
+use hyper::header::ContentType;
+use hyper::server::{Request, Response, Service};
+use hyper_tls::HttpsConnector;
+use models::{Ad, NewAd, StorageConfig, SuppressionRule};
+use r2d2::Pool;
+use r2d2_diesel::ConnectionManager;
+use serde_json;
+use std::collections::HashMap;
+use tokio_core::reactor::Handle;
+use url::form_urlencoded;
+
+#[derive(Deserialize, Debug)]
+pub struct SuppressionRulePost {
+    pub kind: String,
+    pub pattern: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdPost {
+    pub id: String,
+    pub html: String,
+    pub political: Option<bool>,
+    pub targeting: Option<String>,
+}
+
+/// Serves the dashboard's HTTP surface. Reads and suppresses are routed
+/// through `Ad`'s `_async` variants so a slow search or suppress runs on
+/// `pool` instead of blocking the Hyper event loop for other connections.
+/// Suppression-rule management (`/suppression-rules`) is low-traffic admin
+/// tooling, so it talks to Diesel directly rather than through a `pool`
+/// variant.
+#[derive(Clone)]
+pub struct Dashboard {
+    pub db: Pool<ConnectionManager<PgConnection>>,
+    pub pool: CpuPool,
+    pub client: Client<HttpsConnector<HttpConnector>, Body>,
+    pub storage: StorageConfig,
+    pub handle: Handle,
+}
+
+fn json_response(status: StatusCode, body: String) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+fn error_response(e: Error) -> Response {
+    warn!("{:?}", e);
+    Response::new().with_status(StatusCode::InternalServerError)
+}
+
+impl Service for Dashboard {
+    type Request = Request;
+    type Response = Response;
+    type Error = ::hyper::Error;
+    type Future = Box<Future<Item = Response, Error = ::hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let db = self.db.clone();
+        let pool = self.pool.clone();
+
+        match (req.method().clone(), req.path().to_string()) {
+            (Method::Post, ref path) if path == "/ads" => {
+                let options: HashMap<String, String> = req.query()
+                    .map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+                    .unwrap_or_default();
+                let language = options.get("lang").cloned().unwrap_or_else(
+                    || "en-US".to_string(),
+                );
+                let client = self.client.clone();
+                let storage = self.storage.clone();
+                let handle = self.handle.clone();
+                let grab_db = db.clone();
+                let grab_pool = pool.clone();
+
+                Box::new(req.body().concat2().and_then(move |body| {
+                    let ad_post = match serde_json::from_slice(&body) {
+                        Ok(ad_post) => ad_post,
+                        Err(e) => {
+                            return Ok(error_response(
+                                Error::with_chain(e, "Invalid ad payload"),
+                            ))
+                        }
+                    };
+                    let saved = NewAd::new(&ad_post, &language).and_then(
+                        |new_ad| new_ad.save(&db),
+                    );
+                    Ok(match saved {
+                        Ok(ad) => {
+                            // Archiving is best-effort and can take a while (it
+                            // re-downloads every image), so it runs on the
+                            // reactor instead of holding up the response.
+                            handle.spawn(ad.grab_and_store(client, &grab_db, grab_pool, &storage));
+                            json_response(
+                                StatusCode::Ok,
+                                serde_json::to_string(&ad).unwrap_or_default(),
+                            )
+                        }
+                        Err(e) => error_response(e),
+                    })
+                }))
+            }
+            (Method::Get, ref path) if path == "/ads" => {
+                let options: HashMap<String, String> = req.query()
+                    .map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+                    .unwrap_or_default();
+                let language = options.get("lang").cloned().unwrap_or_else(
+                    || "en-US".to_string(),
+                );
+
+                Box::new(Ad::get_ads_by_lang_async(language, db, options, pool).then(
+                    |result| {
+                        Ok(match result {
+                            Ok(ads) => {
+                                json_response(
+                                    StatusCode::Ok,
+                                    serde_json::to_string(&ads).unwrap_or_default(),
+                                )
+                            }
+                            Err(e) => error_response(e),
+                        })
+                    },
+                ))
+            }
+            (Method::Post, ref path) if path.starts_with("/suppress/") => {
+                let adid = path.trim_left_matches("/suppress/").to_string();
+                Box::new(Ad::suppress_async(adid, db, pool).then(|result| {
+                    Ok(match result {
+                        Ok(()) => Response::new().with_status(StatusCode::NoContent),
+                        Err(e) => error_response(e),
+                    })
+                }))
+            }
+            (Method::Get, ref path) if path == "/suppression-rules" => {
+                Box::new(
+                    Ok(match SuppressionRule::list(&db) {
+                        Ok(rules) => {
+                            json_response(
+                                StatusCode::Ok,
+                                serde_json::to_string(&rules).unwrap_or_default(),
+                            )
+                        }
+                        Err(e) => error_response(e),
+                    }).into_future(),
+                )
+            }
+            (Method::Post, ref path) if path == "/suppression-rules" => {
+                Box::new(req.body().concat2().and_then(move |body| {
+                    let rule_post = match serde_json::from_slice::<SuppressionRulePost>(&body) {
+                        Ok(rule_post) => rule_post,
+                        Err(e) => {
+                            return Ok(error_response(
+                                Error::with_chain(e, "Invalid suppression rule payload"),
+                            ))
+                        }
+                    };
+                    Ok(
+                        match SuppressionRule::add(&rule_post.kind, &rule_post.pattern, &db) {
+                            Ok(rule) => {
+                                json_response(
+                                    StatusCode::Ok,
+                                    serde_json::to_string(&rule).unwrap_or_default(),
+                                )
+                            }
+                            Err(e) => error_response(e),
+                        },
+                    )
+                }))
+            }
+            (Method::Delete, ref path) if path.starts_with("/suppression-rules/") => {
+                let response = match path.trim_left_matches("/suppression-rules/").parse::<i32>() {
+                    Ok(rule_id) => {
+                        match SuppressionRule::remove(rule_id, &db) {
+                            Ok(()) => Response::new().with_status(StatusCode::NoContent),
+                            Err(e) => error_response(e),
+                        }
+                    }
+                    Err(_) => Response::new().with_status(StatusCode::BadRequest),
+                };
+                Box::new(Ok(response).into_future())
+            }
+            (Method::Post, ref path) if path == "/suppression-rules/apply" => {
+                Box::new(
+                    Ok(match SuppressionRule::apply_all(&db) {
+                        Ok(affected) => {
+                            json_response(
+                                StatusCode::Ok,
+                                serde_json::to_string(&affected).unwrap_or_default(),
+                            )
+                        }
+                        Err(e) => error_response(e),
+                    }).into_future(),
+                )
+            }
+            _ => Box::new(
+                Ok(Response::new().with_status(StatusCode::NotFound)).into_future(),
+            ),
+        }
+    }
+}